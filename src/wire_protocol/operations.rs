@@ -10,7 +10,9 @@ use std::io::{Read, Write};
 use std::mem;
 use std::result::Result::{Ok, Err};
 
-trait ByteLength {
+/// Computes the serialized size of BSON values without encoding them, e.g. to check a document
+/// against the server's `maxBsonObjectSize` before sending it.
+pub trait ByteLength {
     /// Calculates the number of bytes in the serialized version of the struct.
     fn byte_length(&self) -> Result<i32>;
 }
@@ -67,13 +69,21 @@ impl ByteLength for bson::Bson {
             bson::Bson::FloatingPoint(_) => 8,
             bson::Bson::JavaScriptCode(js) => 5 + js.len() as i32,
             bson::Bson::JavaScriptCodeWithScope(js, s) => 9 + js.len() as i32 + s.byte_length()?,
+            #[cfg(feature = "decimal128")]
+            bson::Bson::Decimal128(_) => 16,
+            // Decimal128 is bson's only feature-gated variant, so this keeps the build working
+            // when another crate turns on bson's decimal128 feature without turning on ours.
+            #[cfg(not(feature = "decimal128"))]
+            #[allow(unreachable_patterns)]
+            _ => 16,
         })
     }
 }
 
 #[cfg(test)]
 mod byte_length_test {
-    use bson::{Bson, bson, doc};
+    use bson::{Bson, bson, doc, oid};
+    use bson::spec::BinarySubtype;
     use super::ByteLength;
 
     #[test]
@@ -98,6 +108,15 @@ mod byte_length_test {
         assert_eq!(Bson::JavaScriptCodeWithScope("alert(\"hello\");".into(), doc!{}).byte_length().unwrap(), 29);
     }
 
+    #[cfg(feature = "decimal128")]
+    #[test]
+    fn test_decimal128_length() {
+        use bson::decimal128::Decimal128;
+
+        assert_eq!(Bson::Decimal128(Decimal128::from_i32(5)).byte_length().unwrap(), 16);
+        assert_eq!(doc!{"price": Bson::Decimal128(Decimal128::zero())}.byte_length().unwrap(), 28);
+    }
+
     #[test]
     fn test_document_lengths() {
         assert_eq!(doc!{}.byte_length().unwrap(), 5);
@@ -120,6 +139,37 @@ mod byte_length_test {
 
     }
 
+    #[test]
+    fn test_matches_encoded_length() {
+        let docs = vec![
+            doc!{},
+            doc!{ "answer": 42, "ratio": 2.5, "big": 1i64 << 40, "name": "mongo" },
+            doc!{ "nested": { "list": [1, "two", { "three": 3 }], "none": Bson::Null } },
+            doc!{
+                "generic": Bson::Binary(BinarySubtype::Generic, b"12345".to_vec()),
+                "old": Bson::Binary(BinarySubtype::BinaryOld, b"123".to_vec()),
+            },
+            doc!{
+                "_id": Bson::ObjectId(oid::ObjectId::with_bytes([1; 12])),
+                "flag": true,
+                "at": Bson::UtcDatetime("2012-06-02T01:12:00.000Z".parse().unwrap()),
+                "ts": Bson::TimeStamp(4),
+            },
+            doc!{
+                "re": Bson::RegExp("^mongo".to_owned(), "i".to_owned()),
+                "code": Bson::JavaScriptCode("return 1;".to_owned()),
+                "scoped": Bson::JavaScriptCodeWithScope("return x;".to_owned(), doc!{ "x": 1 }),
+                "sym": Bson::Symbol("sym".to_owned()),
+            },
+        ];
+
+        for doc in docs {
+            let mut buffer = Vec::new();
+            bson::encode_document(&mut buffer, &doc).unwrap();
+            assert_eq!(doc.byte_length().unwrap() as usize, buffer.len());
+        }
+    }
+
     #[test]
     fn test_complex_document() {
         let doc = bson!({ "isMaster": 1, "client": { "driver": { "name": "mongo-rust-driver-prototype", "version": "0.4.0" }, "os": { "type": "linux", "architecture": "x86_64" } } });
@@ -370,11 +420,7 @@ impl Message {
     ///
     /// Returns nothing on success, or an Error on failure.
     fn write_bson_document<W: Write>(buffer: &mut W, bson: &bson::Document) -> Result<()> {
-        let mut temp_buffer = Vec::new();
-
-        bson::encode_document(&mut temp_buffer, bson)?;
-        buffer.write_all(&temp_buffer)?;
-
+        bson::encode_document(buffer, bson)?;
         Ok(())
     }
 
@@ -628,6 +674,19 @@ impl Message {
     ///
     /// Returns the reply message on success, or an Error on failure.
     fn read_reply<R: Read>(buffer: &mut R, header: Header) -> Result<Message> {
+        // Header, flags, cursor_id, starting_from, and number_returned.
+        let fixed_length = mem::size_of::<Header>() as i32 + 3 * mem::size_of::<i32>() as i32 +
+            mem::size_of::<i64>() as i32;
+
+        // The message length comes straight off the wire; reject anything too short to hold
+        // the fixed fields so the arithmetic below can't underflow.
+        if header.message_length < fixed_length {
+            return Err(ResponseError(format!(
+                "Invalid reply message length from server: {}.",
+                header.message_length
+            )));
+        }
+
         let mut length = header.message_length - mem::size_of::<Header>() as i32;
 
         // Read flags
@@ -649,8 +708,23 @@ impl Message {
         let mut v = Vec::new();
 
         while length > 0 {
-            let bson = bson::decode_document(buffer)?;
-            length -= bson.byte_length()?;
+            // bson::decode_document discards the length prefix and reads up to the terminator,
+            // so read the prefix here and check it against what was actually decoded.
+            let mut prefix = [0; 4];
+            buffer.read_exact(&mut prefix)?;
+            let declared_length = (&prefix[..]).read_i32::<LittleEndian>()?;
+
+            let bson = bson::decode_document(&mut (&prefix[..]).chain(&mut *buffer))?;
+            let decoded_length = bson.byte_length()?;
+            if declared_length != decoded_length {
+                return Err(ResponseError(format!(
+                    "Reply document declared a length of {} bytes but contained {}.",
+                    declared_length,
+                    decoded_length
+                )));
+            }
+
+            length -= decoded_length;
             v.push(bson);
         }
 
@@ -683,3 +757,148 @@ impl Message {
         }
     }
 }
+
+#[cfg(test)]
+mod read_reply_test {
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use std::io::Cursor;
+    use Error::ResponseError;
+    use super::Message;
+
+    fn reply_header(message_length: i32) -> Cursor<Vec<u8>> {
+        let mut buffer = Vec::new();
+        buffer.write_i32::<LittleEndian>(message_length).unwrap();
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(1).unwrap();
+        Cursor::new(buffer)
+    }
+
+    #[test]
+    fn test_reply_length_underflow() {
+        for &length in &[i32::min_value(), -1, 0, 35] {
+            let mut buffer = reply_header(length);
+            match Message::read(&mut buffer) {
+                Err(ResponseError(_)) => (),
+                other => panic!("Expected ResponseError for length {}, got {:?}", length, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_reply() {
+        let mut buffer = reply_header(36);
+        buffer.set_position(16);
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i64::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.set_position(0);
+
+        match Message::read(&mut buffer).unwrap() {
+            Message::OpReply { documents, .. } => assert!(documents.is_empty()),
+            other => panic!("Expected OpReply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reply_document_length_mismatch() {
+        use bson::{self, doc};
+
+        let mut encoded = Vec::new();
+        bson::encode_document(&mut encoded, &doc!{ "a": 1 }).unwrap();
+        let actual_length = encoded.len() as i32;
+        (&mut encoded[..4]).write_i32::<LittleEndian>(200).unwrap();
+
+        let mut buffer = reply_header(36 + actual_length);
+        buffer.set_position(16);
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i64::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(1).unwrap();
+        buffer.get_mut().extend_from_slice(&encoded);
+        buffer.set_position(0);
+
+        match Message::read(&mut buffer) {
+            Err(ResponseError(_)) => (),
+            other => panic!("Expected ResponseError, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "decimal128")]
+    #[test]
+    fn test_decimal128_reply() {
+        use bson::{self, Bson, doc};
+        use bson::decimal128::Decimal128;
+
+        let docs = vec![
+            doc!{ "d": Bson::Decimal128(Decimal128::from_i32(5)) },
+            doc!{ "d": Bson::Decimal128(Decimal128::from_i32(-7)) },
+        ];
+
+        let mut encoded = Vec::new();
+        for doc in &docs {
+            bson::encode_document(&mut encoded, doc).unwrap();
+        }
+
+        let mut buffer = reply_header(36 + encoded.len() as i32);
+        buffer.set_position(16);
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i64::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(0).unwrap();
+        buffer.write_i32::<LittleEndian>(docs.len() as i32).unwrap();
+        buffer.get_mut().extend_from_slice(&encoded);
+        buffer.set_position(0);
+
+        let documents = match Message::read(&mut buffer).unwrap() {
+            Message::OpReply { documents, .. } => documents,
+            other => panic!("Expected OpReply, got {:?}", other),
+        };
+        assert_eq!(buffer.position() as usize, buffer.get_ref().len());
+        assert_eq!(documents.len(), docs.len());
+
+        // Each document is its length, the element type, the "d" key, then the 16 value bytes.
+        let doc_length = encoded.len() / docs.len();
+        for (i, doc) in documents.iter().enumerate() {
+            let start = i * doc_length + 4 + 1 + 2;
+            match doc.get("d") {
+                Some(&Bson::Decimal128(ref d)) => {
+                    assert_eq!(&d.to_raw_bytes_le()[..], &encoded[start..start + 16])
+                }
+                other => panic!("Expected Decimal128, got {:?}", other),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod write_insert_test {
+    use bson::{self, doc};
+    use byteorder::{LittleEndian, ReadBytesExt};
+    use wire_protocol::flags::OpInsertFlags;
+    use super::Message;
+
+    #[test]
+    fn test_documents_written_back_to_back() {
+        let docs = vec![doc!{ "a": 1 }, doc!{ "b": "two" }, doc!{ "c": { "d": 3.0 } }];
+
+        let mut expected = Vec::new();
+        for doc in &docs {
+            bson::encode_document(&mut expected, doc).unwrap();
+        }
+
+        let namespace = "db.coll";
+        let message = Message::new_insert(1, OpInsertFlags::empty(), namespace.to_owned(), docs)
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        message.write(&mut buffer).unwrap();
+
+        let message_length = (&buffer[..4]).read_i32::<LittleEndian>().unwrap();
+        assert_eq!(message_length as usize, buffer.len());
+
+        // Header, flags, and the null-terminated namespace precede the documents.
+        let prefix_length = 16 + 4 + namespace.len() + 1;
+        assert_eq!(&buffer[prefix_length..], &expected[..]);
+    }
+}
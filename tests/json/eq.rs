@@ -72,5 +72,10 @@ pub fn bson_eq(b1: &Bson, b2: &Bson) -> bool {
                        date_time == other_date_time)
         }
         Bson::Symbol(ref s1) => var_match!(*b2, Bson::Symbol(ref s2) => s1 == s2),
+        #[cfg(feature = "decimal128")]
+        Bson::Decimal128(ref d1) => var_match!(*b2, Bson::Decimal128(ref d2) => d1 == d2),
+        #[cfg(not(feature = "decimal128"))]
+        #[allow(unreachable_patterns)]
+        _ => b1 == b2,
     }
 }